@@ -0,0 +1,34 @@
+//! Peer identity and connection management.
+
+use crate::ring::{Location, Services};
+
+/// Stable identifier for a peer on the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub(crate) struct PeerKey(pub(crate) [u8; 32]);
+
+/// A peer's identity paired with its current ring `Location` and the `Services` it
+/// advertises (e.g. whether it relays, stores, or is a gateway).
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub(crate) struct PeerKeyLocation {
+    pub peer: PeerKey,
+    pub location: Location,
+    pub services: Services,
+}
+
+impl PeerKeyLocation {
+    /// Build a `PeerKeyLocation` from a peer's advertised `Services`, captured at
+    /// connection time so `Ring` can filter and prioritize on them afterwards.
+    pub fn new(peer: PeerKey, location: Location, services: Services) -> Self {
+        PeerKeyLocation {
+            peer,
+            location,
+            services,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ConnError {
+    #[error("failed to establish connection to peer")]
+    ConnectionFailed,
+}
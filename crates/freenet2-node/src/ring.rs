@@ -1,16 +1,51 @@
 //! Ring protocol logic and supporting types.
 
-use std::{collections::BTreeMap, convert::TryFrom, fmt::Display, hash::Hasher};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+    fmt::Display,
+    hash::Hasher,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use bitflags::bitflags;
 use parking_lot::RwLock;
 
-use crate::conn_manager::{self, PeerKeyLocation};
+use crate::conn_manager::{self, PeerKey, PeerKeyLocation};
+
+bitflags! {
+    /// Capabilities a peer advertises on its `PeerKeyLocation`, so the ring can be
+    /// heterogeneous (not every node relays, stores or accepts subscriptions) without
+    /// hard-coding roles at the connection layer.
+    pub(crate) struct Services: u8 {
+        const RELAY = 0b0001;
+        const STORE = 0b0010;
+        const SUBSCRIBE = 0b0100;
+        const GATEWAY = 0b1000;
+    }
+}
+
+impl serde::Serialize for Services {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.bits())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Services {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bits = u8::deserialize(deserializer)?;
+        Ok(Services::from_bits_truncate(bits))
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct Ring {
     pub connections_by_location: RwLock<BTreeMap<Location, PeerKeyLocation>>,
     pub rnd_if_htl_above: usize,
     pub max_hops_to_live: usize,
+    my_location: RwLock<Location>,
+    node_table: RwLock<NodeTable>,
 }
 
 impl Ring {
@@ -24,14 +59,20 @@ impl Ring {
     ///
     pub const MAX_HOPS_TO_LIVE: usize = 10;
 
-    pub fn new() -> Self {
+    pub fn new(my_location: Location) -> Self {
         Ring {
             connections_by_location: RwLock::new(BTreeMap::new()),
             rnd_if_htl_above: Self::RAND_WALK_ABOVE_HTL,
             max_hops_to_live: Self::MAX_HOPS_TO_LIVE,
+            my_location: RwLock::new(my_location),
+            node_table: RwLock::new(NodeTable::default()),
         }
     }
 
+    pub fn my_location(&self) -> Location {
+        *self.my_location.read()
+    }
+
     pub fn with_rnd_walk_above(&mut self, rnd_if_htl_above: usize) -> &mut Self {
         self.rnd_if_htl_above = rnd_if_htl_above;
         self
@@ -42,19 +83,77 @@ impl Ring {
         self
     }
 
-    pub fn should_accept(&self, my_location: &Location, location: &Location) -> bool {
+    pub fn should_accept(
+        &self,
+        location: &Location,
+        services: Services,
+        allow_list: &HashSet<PeerKey>,
+    ) -> bool {
+        let my_location = self.my_location();
         let cbl = &*self.connections_by_location.read();
-        if location == my_location || cbl.contains_key(location) {
+        if *location == my_location || cbl.contains_key(location) {
             false
+        } else if services.contains(Services::GATEWAY) {
+            // gateways are always worth connecting to, even past MAX_CONNECTIONS
+            true
         } else if cbl.len() < Self::MIN_CONNECTIONS {
             true
         } else if cbl.len() >= Self::MAX_CONNECTIONS {
-            false
+            // full table: only take this peer if it's closer than the furthest
+            // evictable connection, since accepting it implies pruning one away
+            self.furthest_evictable_distance(cbl, allow_list)
+                .map(|furthest| my_location.distance(location) < furthest)
+                .unwrap_or(false)
         } else {
-            my_location.distance(location) < self.median_distance_to(my_location)
+            my_location.distance(location) < self.median_distance_to(&my_location)
         }
     }
 
+    /// Drop the connections furthest (in ring distance from `self.my_location()`) until
+    /// `connections_by_location` is back down to `ceiling`. Never evicts a peer present in
+    /// `allow_list` (e.g. a gateway connection that must be kept alive) or a peer
+    /// advertising `Services::GATEWAY`, since `should_accept` lets those in over capacity
+    /// in the first place and would otherwise just prune them straight back out.
+    pub(crate) fn prune_connections(&self, ceiling: usize, allow_list: &HashSet<PeerKey>) {
+        let my_location = self.my_location();
+        let mut cbl = self.connections_by_location.write();
+        if cbl.len() <= ceiling {
+            return;
+        }
+        let mut by_distance: Vec<(Distance, Location)> = cbl
+            .iter()
+            .filter(|(_, peer)| Self::is_evictable(peer, allow_list))
+            .map(|(loc, _)| (my_location.distance(loc), *loc))
+            .collect();
+        by_distance.sort_by(|(a, _), (b, _)| b.cmp(a));
+        for (_, location) in by_distance {
+            if cbl.len() <= ceiling {
+                break;
+            }
+            cbl.remove(&location);
+        }
+    }
+
+    /// Distance from `self.my_location()` to the furthest connection that is not protected,
+    /// i.e. the one `prune_connections` would evict first. `None` if every connection is
+    /// protected.
+    fn furthest_evictable_distance(
+        &self,
+        cbl: &BTreeMap<Location, PeerKeyLocation>,
+        allow_list: &HashSet<PeerKey>,
+    ) -> Option<Distance> {
+        let my_location = self.my_location();
+        cbl.iter()
+            .filter(|(_, peer)| Self::is_evictable(peer, allow_list))
+            .map(|(loc, _)| my_location.distance(loc))
+            .max()
+    }
+
+    /// A connection can be pruned unless it's on `allow_list` or advertises `GATEWAY`.
+    fn is_evictable(peer: &PeerKeyLocation, allow_list: &HashSet<PeerKey>) -> bool {
+        !allow_list.contains(&peer.peer) && !peer.services.contains(Services::GATEWAY)
+    }
+
     pub fn median_distance_to(&self, location: &Location) -> Distance {
         let mut conn_by_dist = self.connections_by_distance(location);
         conn_by_dist.sort_by_key(|(k, _)| *k);
@@ -70,17 +169,299 @@ impl Ring {
             .collect()
     }
 
+    /// Pick the next hop for a message travelling towards `target`, `skip`ping peers
+    /// already on its path. Implements Freenet's two-phase forwarding: while there are
+    /// more hops to live than `rnd_if_htl_above`, the request is forwarded to a random
+    /// peer so it disperses before homing in; once `htl` drops to or below that
+    /// threshold, forwarding switches to greedy mode and always picks the connection
+    /// closest to `target`. Returns `None` when no eligible peer remains.
+    pub(crate) fn route_to(
+        &self,
+        target: &Location,
+        htl: usize,
+        skip: &HashSet<PeerKey>,
+    ) -> Option<PeerKeyLocation> {
+        if htl > self.rnd_if_htl_above {
+            self.random_peer(|peer| !skip.contains(&peer.peer))
+        } else {
+            self.connections_by_distance(target)
+                .into_iter()
+                .filter(|(_, peer)| !skip.contains(&peer.peer))
+                .min_by_key(|(dist, _)| *dist)
+                .map(|(_, peer)| peer)
+        }
+    }
+
+    /// Like `connections_by_distance`, but only considers peers advertising every
+    /// service in `required` (e.g. routing a PUT to peers that offer contract storage).
+    pub fn connections_by_distance_with(
+        &self,
+        to: &Location,
+        required: Services,
+    ) -> Vec<(Distance, PeerKeyLocation)> {
+        self.connections_by_location
+            .read()
+            .iter()
+            .filter(|(_, peer)| peer.services.contains(required))
+            .map(|(key, peer)| (key.distance(to), *peer))
+            .collect()
+    }
+
+    /// Uniformly sample one connection matching `filter_fn`, or `None` if none match.
+    /// Used for the random-walk phase of `route_to`, where always returning the same
+    /// (e.g. lowest-location) eligible peer would mean no actual dispersion.
     pub fn random_peer<F>(&self, filter_fn: F) -> Option<PeerKeyLocation>
     where
         F: FnMut(&&PeerKeyLocation) -> bool,
     {
+        use rand::seq::IteratorRandom;
         // FIXME: should be optimized and avoid copying
         self.connections_by_location
             .read()
             .values()
-            .find(filter_fn)
+            .filter(filter_fn)
+            .choose(&mut rand::thread_rng())
             .copied()
     }
+
+    /// Like `random_peer`, but additionally restricted to peers advertising every
+    /// service in `required`.
+    pub fn random_peer_with<F>(&self, required: Services, mut filter_fn: F) -> Option<PeerKeyLocation>
+    where
+        F: FnMut(&&PeerKeyLocation) -> bool,
+    {
+        self.random_peer(|peer| peer.services.contains(required) && filter_fn(peer))
+    }
+
+    /// Metropolis-Hastings location swap with `other`: accept with probability
+    /// `min(1, D_before / D_after)` where `D` is the product of ring distances from each
+    /// side to its own neighbors, rejecting outright on a location collision. Only the
+    /// local half is committed here; the other side must independently accept via the same
+    /// `shared_accept_sample` draw, and neighbors are re-keyed afterwards via
+    /// `update_peer_location`.
+    pub(crate) fn try_swap_location(&self, other: &PeerNeighborhood) -> bool {
+        // Hold the write lock across the whole decide-then-commit step: a concurrent
+        // try_swap_location on this side (e.g. the periodic driver racing an incoming
+        // proposal) must not score its own swap against a location this call is about to
+        // change out from under it.
+        let mut my_location_guard = self.my_location.write();
+        let my_location = *my_location_guard;
+        let my_neighbor_locations: Vec<Location> =
+            self.connections_by_location.read().keys().copied().collect();
+
+        if my_neighbor_locations.contains(&other.location)
+            || other.neighbor_locations.contains(&my_location)
+        {
+            // would collide with an existing neighbor's location
+            return false;
+        }
+
+        let d_before = Self::distance_product(&my_location, &my_neighbor_locations)
+            * Self::distance_product(&other.location, &other.neighbor_locations);
+        let d_after = Self::distance_product(&other.location, &my_neighbor_locations)
+            * Self::distance_product(&my_location, &other.neighbor_locations);
+        let accept_probability = if d_after == 0.0 {
+            1.0
+        } else {
+            (d_before / d_after).min(1.0)
+        };
+
+        if Self::shared_accept_sample(&my_location, &other.location) >= accept_probability {
+            return false;
+        }
+        *my_location_guard = other.location;
+        true
+    }
+
+    /// A `[0, 1)` sample derived from the unordered pair `{a, b}`, so both sides of a
+    /// proposed swap draw the *same* number for it rather than each rolling an
+    /// independent one, without needing a network round-trip to agree on a shared value.
+    fn shared_accept_sample(a: &Location, b: &Location) -> f64 {
+        use std::hash::{Hash, Hasher};
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        lo.hash(&mut hasher);
+        hi.hash(&mut hasher);
+        // keep the top 53 bits so the quotient is exact and strictly below 1.0
+        ((hasher.finish() >> 11) as f64) / ((1u64 << 53) as f64)
+    }
+
+    /// Re-key our record of `peer` from `old_location` to `new_location` after it reports
+    /// having moved (e.g. via a successful `try_swap_location` on its side), so our
+    /// `connections_by_location` doesn't go stale under the peer's previous location. A
+    /// no-op if we have no entry for `peer` at `old_location` anymore.
+    pub(crate) fn update_peer_location(
+        &self,
+        peer: PeerKey,
+        old_location: Location,
+        new_location: Location,
+    ) {
+        let mut cbl = self.connections_by_location.write();
+        match cbl.remove(&old_location) {
+            Some(mut entry) if entry.peer == peer => {
+                entry.location = new_location;
+                cbl.insert(new_location, entry);
+            }
+            Some(stale_entry) => {
+                // `old_location` belongs to a different peer now; leave it alone
+                cbl.insert(old_location, stale_entry);
+            }
+            None => {}
+        }
+    }
+
+    fn distance_product(from: &Location, neighbors: &[Location]) -> f64 {
+        neighbors.iter().map(|n| from.distance(n).as_f64()).product()
+    }
+
+    /// Snapshot `connections_by_location` into the node table and write it to `path`.
+    pub(crate) fn persist_node_table(&self, path: &Path) -> std::io::Result<()> {
+        self.node_table
+            .write()
+            .record_all(self.connections_by_location.read().values().copied());
+        self.node_table.read().save(path)
+    }
+
+    /// Reload the node table previously written by `persist_node_table`.
+    pub(crate) fn load_node_table(&self, path: &Path) -> std::io::Result<()> {
+        *self.node_table.write() = NodeTable::load(path)?;
+        Ok(())
+    }
+
+    /// The `max` nearest peers in the node table to `my_location`, closest first, for
+    /// reconnection attempts on startup. Ties are broken in favor of the more recently seen peer.
+    pub(crate) fn candidates_for_bootstrap(
+        &self,
+        my_location: &Location,
+        max: usize,
+    ) -> Vec<PeerKeyLocation> {
+        self.node_table.read().candidates_by_distance(my_location, max)
+    }
+
+    /// Load the node table from `path` and attempt reconnection, closest-first, against up
+    /// to `max` of its entries via `connect`. Returns the number of peers reconnected.
+    pub(crate) fn bootstrap_from_node_table<F>(
+        &self,
+        path: &Path,
+        max: usize,
+        mut connect: F,
+    ) -> std::io::Result<usize>
+    where
+        F: FnMut(&PeerKeyLocation) -> bool,
+    {
+        self.load_node_table(path)?;
+        let my_location = self.my_location();
+        let mut reconnected = 0;
+        for candidate in self.candidates_for_bootstrap(&my_location, max) {
+            if connect(&candidate) {
+                self.connections_by_location
+                    .write()
+                    .insert(candidate.location, candidate);
+                reconnected += 1;
+            }
+        }
+        Ok(reconnected)
+    }
+}
+
+/// A remote peer's location and the locations of its own neighbors, as needed by the
+/// requesting side of [`Ring::try_swap_location`] to score a swap without holding a lock
+/// on that peer's `Ring`.
+#[derive(Debug, Clone)]
+pub(crate) struct PeerNeighborhood {
+    pub peer: PeerKey,
+    pub location: Location,
+    pub neighbor_locations: Vec<Location>,
+}
+
+/// A peer previously seen in `connections_by_location`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct StoredPeerEntry {
+    peer: PeerKeyLocation,
+    last_seen_unix_secs: u64,
+}
+
+/// On-disk record of the ring's neighborhood, keyed by location like
+/// `connections_by_location` itself.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct NodeTable {
+    entries: BTreeMap<Location, StoredPeerEntry>,
+}
+
+impl NodeTable {
+    fn record_all(&mut self, peers: impl Iterator<Item = PeerKeyLocation>) {
+        let last_seen_unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock is before the unix epoch")
+            .as_secs();
+        for peer in peers {
+            // A peer may have swapped to a new location since its last recorded entry;
+            // drop the stale one so the table doesn't carry the same peer twice.
+            self.entries.retain(|_, entry| entry.peer.peer != peer.peer);
+            self.entries.insert(
+                peer.location,
+                StoredPeerEntry {
+                    peer,
+                    last_seen_unix_secs,
+                },
+            );
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        std::fs::write(path, data)
+    }
+
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            // No table has been persisted yet -- the normal case on first startup.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Nearest entries to `my_location`, closest first; ties broken by more recently seen.
+    fn candidates_by_distance(&self, my_location: &Location, max: usize) -> Vec<PeerKeyLocation> {
+        let mut by_distance: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(location, entry)| (my_location.distance(location), entry))
+            .collect();
+        by_distance.sort_by(|(dist_a, entry_a), (dist_b, entry_b)| {
+            dist_a
+                .cmp(dist_b)
+                .then(entry_b.last_seen_unix_secs.cmp(&entry_a.last_seen_unix_secs))
+        });
+        by_distance
+            .into_iter()
+            .take(max)
+            .map(|(_, entry)| entry.peer)
+            .collect()
+    }
+}
+
+/// Periodically attempt location swaps against freshly discovered neighborhoods, so the
+/// overlay keeps converging on a small-world topology instead of only swapping once at
+/// startup. `next_candidate` is expected to reach a peer via a short random walk and
+/// report back its location and neighbor set.
+pub(crate) fn spawn_location_swap_driver<F>(
+    ring: std::sync::Arc<Ring>,
+    interval: std::time::Duration,
+    mut next_candidate: F,
+) -> std::thread::JoinHandle<()>
+where
+    F: FnMut() -> Option<PeerNeighborhood> + Send + 'static,
+{
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        if let Some(candidate) = next_candidate() {
+            ring.try_swap_location(&candidate);
+        }
+    })
 }
 
 /// An abstract location on the 1D ring, represented by a real number on the interal [0, 1]
@@ -106,6 +487,10 @@ impl Location {
             Location(1.0 - d)
         }
     }
+
+    fn as_f64(&self) -> f64 {
+        self.0
+    }
 }
 
 impl Display for Location {
@@ -164,4 +549,68 @@ pub(crate) enum RingProtoError {
     Join,
     #[error(transparent)]
     ConnError(#[from] Box<conn_manager::ConnError>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_key(byte: u8) -> PeerKey {
+        PeerKey([byte; 32])
+    }
+
+    fn loc(value: f64) -> Location {
+        Location::try_from(value).unwrap()
+    }
+
+    #[test]
+    fn prune_connections_keeps_allow_listed_and_gateway_peers() {
+        let ring = Ring::new(loc(0.5));
+        {
+            let mut cbl = ring.connections_by_location.write();
+            for i in 0..Ring::MAX_CONNECTIONS + 2 {
+                let location = loc(i as f64 / 100.0);
+                let services = if i == 0 {
+                    Services::GATEWAY
+                } else {
+                    Services::empty()
+                };
+                cbl.insert(
+                    location,
+                    PeerKeyLocation::new(peer_key(i as u8), location, services),
+                );
+            }
+        }
+        let gateway_location = loc(0.0);
+        let allow_listed = peer_key(1);
+        let mut allow_list = HashSet::new();
+        allow_list.insert(allow_listed);
+
+        ring.prune_connections(Ring::MAX_CONNECTIONS, &allow_list);
+
+        let cbl = ring.connections_by_location.read();
+        assert_eq!(cbl.len(), Ring::MAX_CONNECTIONS);
+        assert!(cbl.contains_key(&gateway_location));
+        assert!(cbl.values().any(|p| p.peer == allow_listed));
+    }
+
+    #[test]
+    fn node_table_round_trips_through_json() {
+        let mut table = NodeTable::default();
+        let location = loc(0.25);
+        table.record_all(std::iter::once(PeerKeyLocation::new(
+            peer_key(7),
+            location,
+            Services::STORE,
+        )));
+
+        let path = std::env::temp_dir().join(format!("ring-node-table-test-{}.json", std::process::id()));
+        table.save(&path).unwrap();
+        let loaded = NodeTable::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let candidates = loaded.candidates_by_distance(&location, 1);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].peer, peer_key(7));
+    }
 }
\ No newline at end of file